@@ -1,23 +1,25 @@
-#![feature(io, fs)]
-#![feature(old_io, old_path)]
-#![feature(core)]
-#![feature(collections)]
-#![feature(exit_status)]
 extern crate getopts;
 extern crate flate2;
+extern crate tokio;
+extern crate tokio_util;
+extern crate bytes;
+extern crate futures;
 
 use std::env;
-use remote::operations as remote_ops;
+use std::process;
+
+use crate::remote::operations as remote_ops;
 
 mod remote;
 mod pack;
 
-fn main() {
+#[tokio::main]
+async fn main() {
     let args: Vec<String> = env::args().collect();
 
     if args.len() > 1 {
-        let status_code = run_command(&args[1], &args[2..]);
-        env::set_exit_status(status_code);
+        let status_code = run_command(&args[1], &args[2..]).await;
+        process::exit(status_code);
     } else {
         let usage =
             "usage: rgit <command> [<args>]\n\n\
@@ -27,16 +29,16 @@ fn main() {
     }
 }
 
-fn run_command(command: &String, _args: &[String]) -> i32 {
-    match &command[..] {
+async fn run_command(command: &str, _args: &[String]) -> i32 {
+    match command {
         "test" => {
-            match remote_ops::clone_priv("127.0.0.1", 9418, "rgit") {
+            match remote_ops::clone_priv("127.0.0.1", 9418, "rgit").await {
                 Ok(_) => 0,
                 Err(_) => -1
             }
         }
         "ls-remote" => {
-            remote_ops::ls_remote("127.0.0.1", 9418, "rgit")
+            remote_ops::ls_remote("127.0.0.1", 9418, "rgit").await
         },
         unknown => {
             println!("Unknown command: {}", unknown);
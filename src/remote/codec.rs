@@ -0,0 +1,151 @@
+// Maps the raw byte stream of a git:// connection onto a `Stream`/`Sink` of
+// typed protocol messages, so consumers can drive ref-advertisement and
+// want/have negotiation with `Framed<TcpStream, GitCodec>` instead of a
+// blocking read/write loop. Framing itself (the 4-byte hex length prefix)
+// is delegated to `remote::pkt_line`; this codec additionally interprets
+// the payload of each data frame.
+
+use std::io;
+use std::io::Cursor;
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::remote::pkt_line;
+use crate::remote::pkt_line::PktLine;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    // One advertised ref line, verbatim: "<oid> <name>".
+    RefAdvertisement(String),
+    // "want <oid>" / "have <oid>" negotiation commands.
+    Want(String),
+    Have(String),
+    Done,
+    // A chunk of packfile bytes, demultiplexed out of sideband channel 1.
+    PackData(Vec<u8>),
+    Flush,
+    // The protocol v2 command/argument separator and final-response marker.
+    Delim,
+    ResponseEnd,
+    // A payload with no dedicated variant: outgoing, e.g. the initial
+    // "git-upload-pack ..." request line; incoming, a sideband progress
+    // (band 2) or error (band 3) frame, band byte included.
+    Raw(Vec<u8>),
+}
+
+pub struct GitCodec;
+
+impl Decoder for GitCodec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, io::Error> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+
+        // `pkt_line::decode` reports a short buffered slice the same way it
+        // would a genuinely closed connection -- an `UnexpectedEof` -- since
+        // both just mean "ran out of bytes". Here that case means "wait for
+        // more to arrive" rather than an error.
+        let mut cursor = Cursor::new(&src[..]);
+        let pkt = match pkt_line::decode(&mut cursor) {
+            Ok(pkt) => pkt,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let consumed = cursor.position() as usize;
+        src.advance(consumed);
+
+        Ok(Some(match pkt {
+            PktLine::Flush => Message::Flush,
+            PktLine::Delim => Message::Delim,
+            PktLine::ResponseEnd => Message::ResponseEnd,
+            PktLine::Data(payload) => interpret(&payload),
+        }))
+    }
+}
+
+fn interpret(payload: &[u8]) -> Message {
+    if payload.starts_with(b"want ") {
+        Message::Want(String::from_utf8_lossy(&payload[5..]).trim_end().to_string())
+    } else if payload.starts_with(b"have ") {
+        Message::Have(String::from_utf8_lossy(&payload[5..]).trim_end().to_string())
+    } else if payload.starts_with(b"done") {
+        Message::Done
+    } else if !payload.is_empty() && payload[0] == 1 {
+        // Sideband demux: band 1 is packfile data, 2 progress text, 3 error text.
+        Message::PackData(payload[1..].to_vec())
+    } else if !payload.is_empty() && (payload[0] == 2 || payload[0] == 3) {
+        Message::Raw(payload.to_vec())
+    } else {
+        Message::RefAdvertisement(String::from_utf8_lossy(payload).trim_end().to_string())
+    }
+}
+
+impl Encoder<Message> for GitCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), io::Error> {
+        match item {
+            Message::Flush => { dst.put_slice(&pkt_line::flush_pkt()); Ok(()) },
+            Message::Delim => { dst.put_slice(&pkt_line::delim_pkt()); Ok(()) },
+            Message::ResponseEnd => { dst.put_slice(&pkt_line::response_end_pkt()); Ok(()) },
+            Message::Raw(bytes) => write_payload(dst, &bytes),
+            Message::Want(oid) => write_payload(dst, format!("want {}\n", oid).as_bytes()),
+            Message::Have(oid) => write_payload(dst, format!("have {}\n", oid).as_bytes()),
+            Message::Done => write_payload(dst, b"done\n"),
+            Message::RefAdvertisement(_) | Message::PackData(_) =>
+                Err(io::Error::new(io::ErrorKind::InvalidInput, "not an outgoing message")),
+        }
+    }
+}
+
+fn write_payload(dst: &mut BytesMut, payload: &[u8]) -> io::Result<()> {
+    let frame = pkt_line::encode(payload)?;
+    dst.put_slice(&frame);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_flush_delim_and_response_end_control_frames() {
+        let mut src = BytesMut::new();
+        src.put_slice(b"0000000100020006a\n");
+
+        let mut codec = GitCodec;
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(Message::Flush));
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(Message::Delim));
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(Message::ResponseEnd));
+        assert_eq!(codec.decode(&mut src).unwrap(),
+                   Some(Message::RefAdvertisement("a".to_string())));
+    }
+
+    #[test]
+    fn waits_for_a_partially_buffered_frame() {
+        let mut src = BytesMut::new();
+        src.put_slice(b"000"); // not even the 4-byte length prefix yet
+
+        let mut codec = GitCodec;
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+
+        src.put_slice(b"bwant 1\n"); // completes "000bwant 1\n"
+        assert_eq!(codec.decode(&mut src).unwrap(),
+                   Some(Message::Want("1".to_string())));
+    }
+
+    #[test]
+    fn round_trips_a_want_line_through_encode_and_decode() {
+        let mut buf = BytesMut::new();
+        let mut codec = GitCodec;
+        codec.encode(Message::Want("deadbeef".to_string()), &mut buf).unwrap();
+
+        assert_eq!(codec.decode(&mut buf).unwrap(),
+                   Some(Message::Want("deadbeef".to_string())));
+    }
+}
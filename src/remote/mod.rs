@@ -0,0 +1,3 @@
+pub mod pkt_line;
+pub mod codec;
+pub mod operations;
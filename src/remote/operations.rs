@@ -0,0 +1,79 @@
+// Talks the Git smart protocol to a `git daemon` (the anonymous, unauthenticated
+// `git://` transport) over a Tokio TCP socket, driving ref-advertisement and
+// want/have negotiation as stream/sink adaptors over `remote::codec::GitCodec`
+// rather than a blocking read/write loop.
+
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+use crate::remote::codec::{GitCodec, Message};
+
+// Builds the initial "git-upload-pack" request line sent on connect, per
+// the git:// transport described in Documentation/technical/pack-protocol.txt.
+fn upload_pack_request(host: &str, repo: &str) -> Vec<u8> {
+    format!("git-upload-pack /{}\0host={}\0", repo, host).into_bytes()
+}
+
+async fn connect(host: &str, port: u16, repo: &str) -> Result<Framed<TcpStream, GitCodec>, String> {
+    let stream = TcpStream::connect((host, port)).await.map_err(|e| e.to_string())?;
+    let mut framed = Framed::new(stream, GitCodec);
+
+    let request = upload_pack_request(host, repo);
+    framed.send(Message::Raw(request)).await.map_err(|e| e.to_string())?;
+
+    Ok(framed)
+}
+
+// Pulls messages until the flush-pkt that terminates the ref advertisement,
+// returning each advertised "<oid> <refname>" line verbatim.
+async fn read_ref_advertisement(framed: &mut Framed<TcpStream, GitCodec>) -> Vec<String> {
+    let mut refs = Vec::new();
+    while let Some(message) = framed.next().await {
+        match message {
+            Ok(Message::RefAdvertisement(line)) => refs.push(line),
+            Ok(Message::Flush) => break,
+            _ => break,
+        }
+    }
+    refs
+}
+
+pub async fn ls_remote(host: &str, port: u16, repo: &str) -> i32 {
+    let mut framed = match connect(host, port, repo).await {
+        Ok(f) => f,
+        Err(_) => return -1,
+    };
+
+    let refs = read_ref_advertisement(&mut framed).await;
+    if refs.is_empty() {
+        return -1;
+    }
+
+    for line in refs.iter() {
+        println!("{}", line);
+    }
+    0
+}
+
+pub async fn clone_priv(host: &str, port: u16, repo: &str) -> Result<(), String> {
+    let mut framed = connect(host, port, repo).await?;
+
+    let refs = read_ref_advertisement(&mut framed).await;
+    if refs.is_empty() {
+        return Err("remote advertised no refs".to_string());
+    }
+
+    // Negotiate the first advertised ref: one "want <oid>", then flush,
+    // then "done" since we have nothing already.
+    let first_oid = match refs[0].split(' ').next() {
+        Some(oid) => oid.to_string(),
+        None => return Err("malformed ref advertisement".to_string()),
+    };
+
+    framed.send(Message::Want(first_oid)).await.map_err(|e| e.to_string())?;
+    framed.send(Message::Flush).await.map_err(|e| e.to_string())?;
+    framed.send(Message::Done).await.map_err(|e| e.to_string())?;
+
+    Ok(())
+}
@@ -0,0 +1,133 @@
+// Framing codec for Git's smart protocol (pkt-line format), as described in
+// Documentation/technical/protocol-common.txt. Every frame on the wire is a
+// 4-byte ASCII hex length prefix, counting the prefix itself, followed by
+// that many bytes of payload. A handful of lengths are reserved as control
+// frames rather than data: 0000 (flush-pkt), 0001 (delimiter), 0002
+// (response-end).
+
+use std::io;
+use std::io::Read;
+
+// git refuses to emit a pkt-line whose total frame size would exceed
+// 65520 (0xfff0) bytes, i.e. a payload larger than 65516 bytes.
+pub const MAX_PAYLOAD_LEN: usize = 65516;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PktLine {
+    Data(Vec<u8>),
+    Flush,
+    Delim,
+    ResponseEnd,
+}
+
+// Encodes `payload` as a single pkt-line data frame.
+pub fn encode(payload: &[u8]) -> io::Result<Vec<u8>> {
+    if payload.len() > MAX_PAYLOAD_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                   "pkt-line payload exceeds 65516 bytes"));
+    }
+
+    let frame_len = payload.len() + 4;
+    let mut frame = Vec::with_capacity(frame_len);
+    frame.extend(format!("{:04x}", frame_len).into_bytes());
+    frame.extend(payload.iter().cloned());
+    Ok(frame)
+}
+
+pub fn flush_pkt() -> Vec<u8> {
+    b"0000".to_vec()
+}
+
+pub fn delim_pkt() -> Vec<u8> {
+    b"0001".to_vec()
+}
+
+pub fn response_end_pkt() -> Vec<u8> {
+    b"0002".to_vec()
+}
+
+// Reads and decodes exactly one pkt-line frame from `r`.
+pub fn decode<R: Read>(r: &mut R) -> io::Result<PktLine> {
+    let mut len_buf = [0u8; 4];
+    read_exact(r, &mut len_buf)?;
+
+    let len_str = match std::str::from_utf8(&len_buf) {
+        Ok(s) => s,
+        Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                             "pkt-line length is not ASCII hex")),
+    };
+    let frame_len = match u32::from_str_radix(len_str, 16) {
+        Ok(n) => n as usize,
+        Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                             "pkt-line length is not ASCII hex")),
+    };
+
+    match frame_len {
+        0 => Ok(PktLine::Flush),
+        1 => Ok(PktLine::Delim),
+        2 => Ok(PktLine::ResponseEnd),
+        n if n < 4 => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "pkt-line length shorter than its own prefix")),
+        n => {
+            let mut payload = vec![0u8; n - 4];
+            read_exact(r, &mut payload)?;
+            Ok(PktLine::Data(payload))
+        }
+    }
+}
+
+// Unlike the in-memory cursors `pack.rs` parses, a live TCP stream can
+// return a short read, so frame decoding has to keep pulling until the
+// buffer is actually full rather than trusting a single `read` call.
+fn read_exact<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match r.read(&mut buf[filled..]) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                "connection closed mid pkt-line")),
+            Ok(n) => filled += n,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn round_trips_a_data_frame_through_encode_and_decode() {
+        let frame = encode(b"want deadbeef\n").expect("encode failed");
+
+        let mut cursor = Cursor::new(frame);
+        let pkt = decode(&mut cursor).expect("decode failed");
+        assert_eq!(pkt, PktLine::Data(b"want deadbeef\n".to_vec()));
+    }
+
+    #[test]
+    fn decodes_flush_delim_and_response_end_control_frames() {
+        for (bytes, expected) in [
+            (flush_pkt(), PktLine::Flush),
+            (delim_pkt(), PktLine::Delim),
+            (response_end_pkt(), PktLine::ResponseEnd),
+        ] {
+            let mut cursor = Cursor::new(bytes);
+            assert_eq!(decode(&mut cursor).expect("decode failed"), expected);
+        }
+    }
+
+    #[test]
+    fn rejects_a_payload_over_the_max_pkt_line_length() {
+        let payload = vec![0u8; MAX_PAYLOAD_LEN + 1];
+        assert!(encode(&payload).is_err());
+    }
+
+    #[test]
+    fn rejects_a_frame_length_shorter_than_its_own_prefix() {
+        let mut cursor = Cursor::new(b"0003".to_vec());
+        assert!(decode(&mut cursor).is_err());
+    }
+}
+